@@ -0,0 +1,69 @@
+use crate::utils::{download, osmconvert};
+use serde::Deserialize;
+
+// Everything needed to import one city's map, instead of a hand-written function per city. Load
+// one of these from a per-city JSON file and hand it to import_city.
+#[derive(Debug, Deserialize)]
+pub struct CityImportConfig {
+    // Where to download the city-wide OSM extract from (e.g. a Geofabrik URL).
+    pub osm_url: String,
+    // Used for the data/input/<city_name>/ directory layout.
+    pub city_name: String,
+    // The specific map to extract, naming the .poly clip under polygons/ and the output .bin.
+    pub name: String,
+    pub drive_on_right: bool,
+    pub private_offstreet_parking: convert_osm::PrivateOffstreetParking,
+    // Optional input paths, passed unchanged to convert_osm::Options. Absent means the importer
+    // skips that data source.
+    pub clip: Option<String>,
+    pub parking_shapes: Option<String>,
+    pub public_offstreet_parking: Option<String>,
+    pub sidewalks: Option<String>,
+    pub elevation: Option<String>,
+}
+
+impl CityImportConfig {
+    pub fn load(path: &str) -> CityImportConfig {
+        abstutil::read_json(path.to_string(), &mut abstutil::Timer::throwaway())
+    }
+}
+
+// Download, clip, convert, and save one city's map using the given config.
+pub fn import_city(config: &CityImportConfig) {
+    let city_osm = format!(
+        "../data/input/{}/osm/{}.osm",
+        config.city_name, config.city_name
+    );
+    download(&city_osm, &config.osm_url);
+
+    println!("Importing {} with name {}", config.city_name, config.name);
+    osmconvert(
+        &city_osm,
+        format!(
+            "../data/input/{}/polygons/{}.poly",
+            config.city_name, config.name
+        ),
+        format!("../data/input/{}/osm/{}.osm", config.city_name, config.name),
+    );
+
+    println!("- Running convert_osm on {}", config.city_name);
+    let map = convert_osm::convert(
+        convert_osm::Options {
+            osm_input: format!("../data/input/{}/osm/{}.osm", config.city_name, config.name),
+            city_name: config.city_name.clone(),
+            name: config.name.clone(),
+
+            parking_shapes: config.parking_shapes.clone(),
+            public_offstreet_parking: config.public_offstreet_parking.clone(),
+            private_offstreet_parking: config.private_offstreet_parking.clone(),
+            sidewalks: config.sidewalks.clone(),
+            elevation: config.elevation.clone(),
+            clip: config.clip.clone(),
+            drive_on_right: config.drive_on_right,
+        },
+        &mut abstutil::Timer::throwaway(),
+    );
+    let output = format!("../data/input/raw_maps/{}.bin", config.name);
+    println!("- Saving {}'s output to {}", config.city_name, output);
+    abstutil::write_binary(output, &map);
+}
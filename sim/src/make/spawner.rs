@@ -1,11 +1,61 @@
 use crate::{
-    Command, DrivingGoal, PersonID, Scheduler, SidewalkPOI, SidewalkSpot, TripEndpoint, TripLeg,
-    TripManager, VehicleSpec, VehicleType, BIKE_LENGTH, MAX_CAR_LENGTH,
+    CapSimState, Command, DrivingGoal, PersonID, Scheduler, SidewalkPOI, SidewalkSpot,
+    TripEndpoint, TripLeg, TripManager, VehicleSpec, VehicleType, BIKE_LENGTH, MAX_CAR_LENGTH,
 };
 use abstutil::Timer;
-use geom::{Speed, Time, EPSILON_DIST};
-use map_model::{BuildingID, BusRouteID, BusStopID, Map, PathConstraints, PathRequest, Position};
+use geom::{Distance, Duration, Speed, Time, EPSILON_DIST};
+use map_model::{
+    BuildingID, BusRouteID, BusStopID, Map, PathConstraints, PathRequest, Position, RoadID,
+};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// When a vehicle can't fit on its start lane yet, wait this long and blindly try again, rather
+// than dropping the trip. Congested borders queue vehicles instead of losing them.
+pub const BLIND_RETRY_TO_SPAWN: Duration = Duration::const_seconds(5.0);
+
+// How long to defer a trip when its first road is already at its configured inflow cap.
+pub const CONGESTION_CAP_RETRY: Duration = Duration::const_seconds(300.0);
+
+// A person's initial epidemic state, used to seed the PandemicModel with reproducible starting
+// outbreaks. Propagated onto the person at spawn time.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum Health {
+    Susceptible,
+    Infected { since: Time },
+    Recovered,
+}
+
+// One step of an expanded multi-hop transit trip, independent of concrete map geometry so the
+// leg ordering can be checked without a Map. Generic over the route/stop id types for the same
+// reason.
+#[derive(Debug, PartialEq)]
+enum TransitLeg<R, S> {
+    WalkToStop(S),
+    RideBus(R, S),
+    WalkToGoal,
+}
+
+// The ordered legs a UsingTransit trip expands into: walk to the first board stop, then for each
+// hop ride the bus and (unless it's the last hop) walk to the next board stop, ending with a walk
+// to the goal. `hops` must be non-empty; schedule_trip aborts degenerate trips before this.
+fn plan_transit_legs<R: Copy, S: Copy>(hops: &[(R, S, S)]) -> Vec<TransitLeg<R, S>> {
+    let mut legs = vec![TransitLeg::WalkToStop(hops[0].1)];
+    for (idx, (route, _, alight)) in hops.iter().enumerate() {
+        legs.push(TransitLeg::RideBus(*route, *alight));
+        if let Some((_, next_board, _)) = hops.get(idx + 1) {
+            legs.push(TransitLeg::WalkToStop(*next_board));
+        }
+    }
+    legs.push(TransitLeg::WalkToGoal);
+    legs
+}
+
+// Whether a CarAppearing trip physically fits at `start` on a lane of `lane_len` for a vehicle of
+// `vehicle_len`: it must clear its own length from the lane's start and sit before the lane's end.
+fn car_start_fits(start: Distance, vehicle_len: Distance, lane_len: Distance) -> bool {
+    start >= vehicle_len && start < lane_len
+}
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub enum TripSpec {
@@ -35,24 +85,68 @@ pub enum TripSpec {
     UsingTransit {
         start: SidewalkSpot,
         goal: SidewalkSpot,
+        // Each hop is (route, board stop, alight stop). Consecutive hops are joined by a short
+        // walk between the alight stop of one and the board stop of the next, letting a trip
+        // transfer across multiple lines.
+        hops: Vec<(BusRouteID, BusStopID, BusStopID)>,
+        ped_speed: Speed,
+    },
+    // Drive from a building to a parking spot near a transit stop, then ride the bus the rest of
+    // the way. A common commute mode that pure-driving or pure-transit trips can't express.
+    ParkAndRide {
+        start_bldg: BuildingID,
+        park_near: BuildingID,
         route: BusRouteID,
         stop1: BusStopID,
         stop2: BusStopID,
+        goal: SidewalkSpot,
+        vehicle_spec: VehicleSpec,
         ped_speed: Speed,
     },
 }
 
 // This structure is created temporarily by a Scenario or to interactively spawn agents.
 pub struct TripSpawner {
-    trips: Vec<(PersonID, Time, TripSpec)>,
+    trips: Vec<(PersonID, Time, TripSpec, Option<Health>)>,
+    // Trips that failed validation when scheduled. We still record them so they show up as
+    // aborted trips in analytics, instead of silently vanishing.
+    aborted: Vec<(PersonID, Time, TripSpec, Option<Health>)>,
+    // Optional per-road hourly inflow caps. A road not present here is unmetered.
+    road_caps: BTreeMap<RoadID, usize>,
+    // How long to defer a trip whose first road is at its cap.
+    cap_retry_delay: Duration,
 }
 
 impl TripSpawner {
     pub fn new() -> TripSpawner {
-        TripSpawner { trips: Vec::new() }
+        TripSpawner {
+            trips: Vec::new(),
+            aborted: Vec::new(),
+            road_caps: BTreeMap::new(),
+            cap_retry_delay: CONGESTION_CAP_RETRY,
+        }
     }
 
-    pub fn schedule_trip(&mut self, person: PersonID, start_time: Time, spec: TripSpec, map: &Map) {
+    // Limit how many vehicles may enter `road` per hour. Trips whose first road is already at its
+    // cap for the current window are deferred instead of injected.
+    pub fn set_road_cap(&mut self, road: RoadID, vehicles_per_hour: usize) {
+        self.road_caps.insert(road, vehicles_per_hour);
+    }
+
+    // Override how long a capped trip waits before trying again.
+    pub fn set_cap_retry_delay(&mut self, delay: Duration) {
+        self.cap_retry_delay = delay;
+    }
+
+    pub fn schedule_trip(
+        &mut self,
+        person: PersonID,
+        start_time: Time,
+        spec: TripSpec,
+        map: &Map,
+        // The person's initial health state, if this scenario seeds an outbreak.
+        health: Option<Health>,
+    ) {
         // TODO We'll want to repeat this validation when we spawn stuff later for a second leg...
         match &spec {
             TripSpec::CarAppearing {
@@ -61,33 +155,35 @@ impl TripSpawner {
                 goal,
                 ..
             } => {
-                if start_pos.dist_along() < vehicle_spec.length {
-                    panic!(
-                        "Can't spawn a {:?} at {}; too close to the start",
-                        vehicle_spec.vehicle_type,
-                        start_pos.dist_along()
-                    );
-                }
-                if start_pos.dist_along() >= map.get_l(start_pos.lane()).length() {
-                    panic!(
-                        "Can't spawn a {:?} at {}; {} isn't that long",
+                let dist = start_pos.dist_along();
+                let lane_len = map.get_l(start_pos.lane()).length();
+                if !car_start_fits(dist, vehicle_spec.length, lane_len) {
+                    let why = if dist < vehicle_spec.length {
+                        "too close to the start"
+                    } else {
+                        "past the end of the lane"
+                    };
+                    println!(
+                        "Can't spawn a {:?} at {} on {}; {}",
                         vehicle_spec.vehicle_type,
-                        start_pos.dist_along(),
-                        start_pos.lane()
+                        dist,
+                        start_pos.lane(),
+                        why
                     );
+                    self.aborted.push((person, start_time, spec.clone(), health));
+                    return;
                 }
-                match goal {
-                    DrivingGoal::Border(_, end_lane) => {
-                        if start_pos.lane() == *end_lane
-                            && start_pos.dist_along() == map.get_l(*end_lane).length()
-                        {
-                            panic!(
-                                "Can't start a {:?} at the edge of a border already",
-                                vehicle_spec.vehicle_type
-                            );
-                        }
+                if let DrivingGoal::Border(_, end_lane) = goal {
+                    if start_pos.lane() == *end_lane
+                        && start_pos.dist_along() == map.get_l(*end_lane).length()
+                    {
+                        println!(
+                            "Can't start a {:?} at the edge of a border already",
+                            vehicle_spec.vehicle_type
+                        );
+                        self.aborted.push((person, start_time, spec.clone(), health));
+                        return;
                     }
-                    DrivingGoal::ParkNear(_) => {}
                 }
             }
             TripSpec::UsingParkedCar { .. } => {}
@@ -105,13 +201,12 @@ impl TripSpawner {
                 ped_speed,
                 ..
             } => {
-                // TODO These trips are just silently erased; they don't even show up as aborted
-                // trips! Really need to fix the underlying problem.
                 if SidewalkSpot::bike_from_bike_rack(start.sidewalk_pos.lane(), map).is_none() {
                     println!(
                         "Can't start biking from {}; no biking or driving lane nearby?",
                         start.sidewalk_pos.lane()
                     );
+                    self.aborted.push((person, start_time, spec.clone(), health));
                     return;
                 }
                 if let DrivingGoal::ParkNear(b) = goal {
@@ -126,6 +221,7 @@ impl TripSpawner {
                             "Can't fulfill {:?} for a bike trip; no sidewalk near {}",
                             goal, last_lane
                         );
+                        self.aborted.push((person, start_time, spec.clone(), health));
                         return;
                     }
                     // A bike trip going from one lane to the same lane should... just walk.
@@ -142,15 +238,46 @@ impl TripSpawner {
                                 goal: SidewalkSpot::building(*b, map),
                                 ped_speed: *ped_speed,
                             },
+                            health,
                         ));
                         return;
                     }
                 }
             }
-            TripSpec::UsingTransit { .. } => {}
+            TripSpec::UsingTransit { hops, .. } => {
+                // A transit trip needs at least one hop; otherwise there's no bus to board and
+                // later leg-building would index an empty list. Record it as aborted instead.
+                if hops.is_empty() {
+                    println!("A transit trip with no hops doesn't make sense");
+                    self.aborted.push((person, start_time, spec.clone(), health));
+                    return;
+                }
+                // Consecutive hops transfer on foot, so the alight stop of one hop must be able to
+                // reach the board stop of the next.
+                for pair in hops.windows(2) {
+                    let alight = SidewalkSpot::bus_stop(pair[0].2, map);
+                    let board = SidewalkSpot::bus_stop(pair[1].1, map);
+                    if map
+                        .pathfind(PathRequest {
+                            start: alight.sidewalk_pos,
+                            end: board.sidewalk_pos,
+                            constraints: PathConstraints::Pedestrian,
+                        })
+                        .is_none()
+                    {
+                        println!(
+                            "Can't transfer from {:?} to {:?} on foot",
+                            pair[0].2, pair[1].1
+                        );
+                        self.aborted.push((person, start_time, spec.clone(), health));
+                        return;
+                    }
+                }
+            }
+            TripSpec::ParkAndRide { .. } => {}
         };
 
-        self.trips.push((person, start_time, spec));
+        self.trips.push((person, start_time, spec, health));
     }
 
     pub fn finalize(
@@ -159,8 +286,90 @@ impl TripSpawner {
         trips: &mut TripManager,
         scheduler: &mut Scheduler,
         timer: &mut Timer,
-        _retry_if_no_room: bool,
+        retry_if_no_room: bool,
+        mut cap: Option<&mut CapSimState>,
     ) {
+        // Record trips that failed validation in schedule_trip as aborted, so they're still visible
+        // in analytics instead of disappearing.
+        for (p, start_time, spec, health) in std::mem::replace(&mut self.aborted, Vec::new()) {
+            let person = trips.get_person(p).unwrap().clone();
+            if let Some(health) = health {
+                trips.set_initial_health(person.id, health);
+            }
+            let (trip_start, legs) = match spec {
+                TripSpec::CarAppearing {
+                    start_pos,
+                    vehicle_spec,
+                    goal,
+                    ..
+                } => {
+                    let vehicle = if vehicle_spec.vehicle_type == VehicleType::Car {
+                        vehicle_spec.make(person.car.unwrap(), Some(person.id))
+                    } else {
+                        vehicle_spec.make(person.bike.unwrap(), Some(person.id))
+                    };
+                    (
+                        TripEndpoint::Border(map.get_l(start_pos.lane()).src_i),
+                        vec![TripLeg::Drive(vehicle, goal)],
+                    )
+                }
+                TripSpec::UsingBike {
+                    start,
+                    vehicle,
+                    goal,
+                    ..
+                } => (
+                    match start.connection {
+                        SidewalkPOI::Building(b) => TripEndpoint::Bldg(b),
+                        SidewalkPOI::SuddenlyAppear => {
+                            TripEndpoint::Border(map.get_l(start.sidewalk_pos.lane()).src_i)
+                        }
+                        SidewalkPOI::Border(i) => TripEndpoint::Border(i),
+                        _ => unreachable!(),
+                    },
+                    vec![TripLeg::Drive(vehicle.make(person.bike.unwrap(), None), goal)],
+                ),
+                TripSpec::UsingTransit {
+                    start,
+                    hops,
+                    goal,
+                    ped_speed,
+                } => (
+                    match start.connection {
+                        SidewalkPOI::Building(b) => TripEndpoint::Bldg(b),
+                        SidewalkPOI::SuddenlyAppear => {
+                            TripEndpoint::Border(map.get_l(start.sidewalk_pos.lane()).src_i)
+                        }
+                        SidewalkPOI::Border(i) => TripEndpoint::Border(i),
+                        _ => unreachable!(),
+                    },
+                    // Walk towards the first board stop, or straight to the goal if the trip was
+                    // aborted for having no hops at all.
+                    vec![TripLeg::Walk(
+                        person.ped,
+                        ped_speed,
+                        hops.first()
+                            .map(|hop| SidewalkSpot::bus_stop(hop.1, map))
+                            .unwrap_or(goal),
+                    )],
+                ),
+                _ => unreachable!(),
+            };
+            let trip = trips.new_trip(person.id, start_time, trip_start, legs);
+            trips.abort_trip_failed_start(trip);
+        }
+
+        // Hand the configured inflow caps to the long-lived CapSimState so that every attempt to
+        // start a trip -- including deferred retries that re-enter through Command::StartTrip --
+        // is gated against the road's current window. Gating only here would let a deferred trip
+        // start unconditionally once its delay elapsed, just shifting a saturated burst later.
+        if let Some(cap) = cap.as_mut() {
+            for (&road, &limit) in &self.road_caps {
+                cap.set_cap(road, limit);
+            }
+            cap.set_retry_delay(self.cap_retry_delay);
+        }
+
         let paths = timer.parallelize(
             "calculate paths",
             std::mem::replace(&mut self.trips, Vec::new()),
@@ -171,7 +380,7 @@ impl TripSpawner {
         );
 
         timer.start_iter("spawn trips", paths.len());
-        for ((p, start_time, spec), maybe_req, maybe_path) in paths {
+        for ((p, start_time, spec, health), maybe_req, maybe_path) in paths {
             timer.next();
 
             // TODO clone() is super weird to do here, but we just need to make the borrow checker
@@ -268,13 +477,26 @@ impl TripSpawner {
                 }
                 TripSpec::UsingTransit {
                     start,
-                    route,
-                    stop1,
-                    stop2,
+                    hops,
                     goal,
                     ped_speed,
                 } => {
-                    let walk_to = SidewalkSpot::bus_stop(stop1, map);
+                    // Walk to the first board stop, then for each hop ride the bus and (unless it's
+                    // the last hop) walk to the next board stop, finishing with a walk to the goal.
+                    let legs = plan_transit_legs(&hops)
+                        .into_iter()
+                        .map(|leg| match leg {
+                            TransitLeg::WalkToStop(stop) => {
+                                TripLeg::Walk(person.ped, ped_speed, SidewalkSpot::bus_stop(stop, map))
+                            }
+                            TransitLeg::RideBus(route, alight) => {
+                                TripLeg::RideBus(person.ped, route, alight)
+                            }
+                            TransitLeg::WalkToGoal => {
+                                TripLeg::Walk(person.ped, ped_speed, goal.clone())
+                            }
+                        })
+                        .collect();
                     trips.new_trip(
                         person.id,
                         start_time,
@@ -286,17 +508,43 @@ impl TripSpawner {
                             SidewalkPOI::Border(i) => TripEndpoint::Border(i),
                             _ => unreachable!(),
                         },
+                        legs,
+                    )
+                }
+                TripSpec::ParkAndRide {
+                    start_bldg,
+                    park_near,
+                    route,
+                    stop1,
+                    stop2,
+                    goal,
+                    vehicle_spec,
+                    ped_speed,
+                } => {
+                    let vehicle = vehicle_spec.make(person.car.unwrap(), Some(person.id));
+                    trips.new_trip(
+                        person.id,
+                        start_time,
+                        TripEndpoint::Bldg(start_bldg),
                         vec![
-                            TripLeg::Walk(person.ped, ped_speed, walk_to.clone()),
+                            TripLeg::Drive(vehicle, DrivingGoal::ParkNear(park_near)),
+                            TripLeg::Walk(person.ped, ped_speed, SidewalkSpot::bus_stop(stop1, map)),
                             TripLeg::RideBus(person.ped, route, stop2),
                             TripLeg::Walk(person.ped, ped_speed, goal),
                         ],
                     )
                 }
             };
+            // Seed the person's starting epidemic state so the PandemicModel can begin from the
+            // outbreak this scenario specified.
+            if let Some(health) = health {
+                trips.set_initial_health(person.id, health);
+            }
+            // The StartTrip handler consults the CapSimState and re-defers the trip if its first
+            // road is still saturated, so there's nothing to gate here -- just schedule it.
             scheduler.push(
                 start_time,
-                Command::StartTrip(trip, spec, maybe_req, maybe_path),
+                Command::StartTrip(trip, retry_if_no_room, spec, maybe_req, maybe_path),
             );
         }
     }
@@ -321,6 +569,20 @@ impl TripSpec {
         }
     }
 
+    // The first road a CarAppearing or UsingBike trip enters, used to apply inflow caps. Every
+    // other trip, including UsingParkedCar and ParkAndRide, returns None and is never gated.
+    // Consulted by the StartTrip handler to gate each (re)attempt against the road's cap.
+    pub(crate) fn gated_road(&self, map: &Map) -> Option<RoadID> {
+        match self {
+            TripSpec::CarAppearing { start_pos, .. } => Some(map.get_parent(start_pos.lane()).id),
+            TripSpec::UsingBike { start, .. } => {
+                SidewalkSpot::bike_from_bike_rack(start.sidewalk_pos.lane(), map)
+                    .map(|spot| map.get_parent(spot.sidewalk_pos.lane()).id)
+            }
+            _ => None,
+        }
+    }
+
     pub(crate) fn get_pathfinding_request(&self, map: &Map) -> Option<PathRequest> {
         match self {
             TripSpec::CarAppearing {
@@ -350,11 +612,68 @@ impl TripSpec {
                     .sidewalk_pos,
                 constraints: PathConstraints::Pedestrian,
             }),
-            TripSpec::UsingTransit { start, stop1, .. } => Some(PathRequest {
+            // Only the initial walk to the first board stop needs pathing up front.
+            TripSpec::UsingTransit { start, hops, .. } => Some(PathRequest {
                 start: start.sidewalk_pos,
-                end: SidewalkSpot::bus_stop(*stop1, map).sidewalk_pos,
+                end: SidewalkSpot::bus_stop(hops[0].1, map).sidewalk_pos,
                 constraints: PathConstraints::Pedestrian,
             }),
+            // Path the driving leg from the building to a spot near the parking destination.
+            TripSpec::ParkAndRide {
+                start_bldg,
+                park_near,
+                ..
+            } => Position::bldg_via_driving(*start_bldg, map).map(|start| PathRequest {
+                start,
+                end: DrivingGoal::ParkNear(*park_near).goal_pos(PathConstraints::Car, map),
+                constraints: PathConstraints::Car,
+            }),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_hop_transit_interleaves_walks_and_rides() {
+        // Route ids and stop ids stand in for the real map types; only the ordering matters here.
+        let hops = vec![('a', 1, 2), ('b', 3, 4)];
+        assert_eq!(
+            plan_transit_legs(&hops),
+            vec![
+                TransitLeg::WalkToStop(1),
+                TransitLeg::RideBus('a', 2),
+                TransitLeg::WalkToStop(3),
+                TransitLeg::RideBus('b', 4),
+                TransitLeg::WalkToGoal,
+            ]
+        );
+    }
+
+    #[test]
+    fn single_hop_transit_has_no_transfer_walk() {
+        let hops = vec![('a', 1, 2)];
+        assert_eq!(
+            plan_transit_legs(&hops),
+            vec![
+                TransitLeg::WalkToStop(1),
+                TransitLeg::RideBus('a', 2),
+                TransitLeg::WalkToGoal,
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_car_starts_are_rejected() {
+        let lane = Distance::meters(50.0);
+        let vehicle = Distance::meters(5.0);
+        // Too close to the start of the lane to fit the vehicle's length.
+        assert!(!car_start_fits(Distance::meters(2.0), vehicle, lane));
+        // At or past the end of the lane.
+        assert!(!car_start_fits(lane, vehicle, lane));
+        // A spot with room on both sides is fine.
+        assert!(car_start_fits(Distance::meters(10.0), vehicle, lane));
+    }
+}